@@ -1,6 +1,61 @@
 // Copyright © 2015-2018, Peter Atashian
 //! Audio in NX files
 
+use file::Error;
+
+/// The WZ audio header is a fixed 82 bytes.
+const HEADER_LEN: usize = 82;
+/// The embedded `WAVEFORMATEX` is right-aligned against the end of the header.
+/// An MP3 `MPEGLAYER3WAVEFORMAT` is 30 bytes, so it begins here...
+const MP3_WAVEFORMAT_OFFSET: usize = HEADER_LEN - 30;
+/// ...while a bare PCM `WAVEFORMATEX` is 18 bytes and begins 12 bytes later.
+const PCM_WAVEFORMAT_OFFSET: usize = HEADER_LEN - 18;
+/// `wFormatTag` value identifying an MPEG Layer-3 stream.
+const WAVE_FORMAT_MPEGLAYER3: u16 = 0x0055;
+
+/// The decoded `WAVEFORMATEX` carried in a WZ audio header.
+#[derive(Clone, Copy, Debug)]
+pub struct AudioFormat {
+    /// Codec identifier, e.g. `0x0001` for PCM or `0x0055` for MP3.
+    pub format_tag: u16,
+    /// Number of interleaved channels.
+    pub channels: u16,
+    /// Sample rate in Hz.
+    pub samples_per_sec: u32,
+    /// Required average transfer rate in bytes per second.
+    pub avg_bytes_per_sec: u32,
+    /// Block alignment in bytes.
+    pub block_align: u16,
+    /// Bits per sample.
+    pub bits_per_sample: u16,
+    /// Size in bytes of the extension that follows, if any.
+    pub extra_size: u16,
+    /// The MP3 extension fields, present only when `format_tag == 0x0055`.
+    pub mp3: Option<Mp3Format>,
+}
+impl AudioFormat {
+    /// Whether the payload is raw PCM.
+    #[inline]
+    pub fn is_pcm(&self) -> bool {
+        self.format_tag == 0x0001
+    }
+}
+
+/// The `MPEGLAYER3WAVEFORMAT` extension fields.
+#[derive(Clone, Copy, Debug)]
+pub struct Mp3Format {
+    /// MPEG Layer-3 codec flag, normally `1` (`MPEGLAYER3_ID_MPEG`).
+    pub id: u16,
+    /// Padding flags.
+    pub flags: u32,
+    /// Size of a single MP3 block in bytes.
+    pub block_size: u16,
+    /// Number of frames per block.
+    pub frames_per_block: u16,
+    /// Encoder/decoder delay in samples.
+    pub codec_delay: u16,
+}
+
 /// Some audio, possibly a sound effect or music
 #[derive(Clone, Copy)]
 pub struct Audio<'a> {
@@ -28,6 +83,86 @@ impl<'a> Audio<'a> {
         unsafe { &*(self.data.as_ptr() as *const [u8; 82]) }
     }
 
+    /// Decodes the `WAVEFORMATEX` embedded in the WZ audio header.
+    ///
+    /// Every field is read little-endian with an up-front bounds check, so a
+    /// header that is too short yields `Error::TooShort` rather than reading
+    /// out of bounds.
+    pub fn format(&self) -> Result<AudioFormat, Error> {
+        if self.data.len() < HEADER_LEN {
+            return Err(Error::TooShort);
+        }
+        let at_u16 = |o: usize| (self.data[o] as u16) | ((self.data[o + 1] as u16) << 8);
+        // The structure is right-aligned, so its start depends on its length:
+        // probe the MP3 tag at the 30-byte position and fall back to the
+        // 18-byte PCM position, which is where every non-MP3 header lands.
+        let base = if at_u16(MP3_WAVEFORMAT_OFFSET) == WAVE_FORMAT_MPEGLAYER3 {
+            MP3_WAVEFORMAT_OFFSET
+        } else {
+            PCM_WAVEFORMAT_OFFSET
+        };
+        let h = &self.data[base..];
+        let read_u16 = |o: usize| (h[o] as u16) | ((h[o + 1] as u16) << 8);
+        let read_u32 = |o: usize| (h[o] as u32) | ((h[o + 1] as u32) << 8)
+            | ((h[o + 2] as u32) << 16) | ((h[o + 3] as u32) << 24);
+        let format_tag = read_u16(0);
+        let mp3 = if format_tag == WAVE_FORMAT_MPEGLAYER3 {
+            Some(Mp3Format {
+                id: read_u16(18),
+                flags: read_u32(20),
+                block_size: read_u16(24),
+                frames_per_block: read_u16(26),
+                codec_delay: read_u16(28),
+            })
+        } else {
+            None
+        };
+        Ok(AudioFormat {
+            format_tag: format_tag,
+            channels: read_u16(2),
+            samples_per_sec: read_u32(4),
+            avg_bytes_per_sec: read_u32(8),
+            block_align: read_u16(12),
+            bits_per_sample: read_u16(14),
+            extra_size: read_u16(16),
+            mp3: mp3,
+        })
+    }
+
+    /// Wraps the PCM payload in a RIFF/WAVE container ready to write to disk.
+    ///
+    /// Returns `None` when the header cannot be decoded or the payload is not
+    /// PCM, since only PCM maps directly onto a bare `fmt `/`data` WAV.
+    #[cfg(feature = "std")]
+    pub fn to_wav(&self) -> Option<Vec<u8>> {
+        let fmt = match self.format() {
+            Ok(fmt) => fmt,
+            Err(_) => return None,
+        };
+        if !fmt.is_pcm() {
+            return None;
+        }
+        let payload = self.data();
+        let mut out = Vec::with_capacity(44 + payload.len());
+        let push_u16 = |out: &mut Vec<u8>, v: u16| out.extend_from_slice(&v.to_le_bytes());
+        let push_u32 = |out: &mut Vec<u8>, v: u32| out.extend_from_slice(&v.to_le_bytes());
+        out.extend_from_slice(b"RIFF");
+        push_u32(&mut out, 36 + payload.len() as u32);
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        push_u32(&mut out, 16);
+        push_u16(&mut out, fmt.format_tag);
+        push_u16(&mut out, fmt.channels);
+        push_u32(&mut out, fmt.samples_per_sec);
+        push_u32(&mut out, fmt.avg_bytes_per_sec);
+        push_u16(&mut out, fmt.block_align);
+        push_u16(&mut out, fmt.bits_per_sample);
+        out.extend_from_slice(b"data");
+        push_u32(&mut out, payload.len() as u32);
+        out.extend_from_slice(payload);
+        Some(out)
+    }
+
     /// Index of the audio within the offset table
     #[inline]
     pub fn index(&self) -> u32 {
@@ -1,6 +1,20 @@
 // Copyright © 2015-2018, Peter Atashian
 //! Bitmaps in NX files
 use lz4::{decompress};
+#[cfg(feature = "std")]
+use std::io::{Error as IoError, Write};
+
+/// The codec used to store a bitmap's decompressed pixel data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw, uncompressed BGRA8888 pixels.
+    None,
+    /// LZ4 block compression, the historical NX default.
+    Lz4,
+    /// Zstandard compression.
+    #[cfg(feature = "compress-zstd")]
+    Zstd,
+}
 
 /// Represents a bitmap
 #[derive(Clone, Copy)]
@@ -8,6 +22,7 @@ pub struct Bitmap<'a> {
     width: u16,
     height: u16,
     data: &'a [u8],
+    compression: Compression,
 }
 impl<'a> Bitmap<'a> {
     /// The width in pixels
@@ -25,21 +40,184 @@ impl<'a> Bitmap<'a> {
     pub fn len(&self) -> u32 {
         self.width as u32 * self.height as u32 * 4
     }
-    /// Creates a `Bitmap` from the supplied data
+    /// The codec used to store this bitmap's pixel data
+    #[inline]
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+    /// Creates a `Bitmap` from the supplied LZ4-compressed data
     #[inline]
     pub unsafe fn construct(data: &'a [u8], width: u16, height: u16) -> Bitmap<'a> {
-        Bitmap { width: width, height: height, data: data }
+        Bitmap::construct_with(data, width, height, Compression::Lz4)
+    }
+    /// Creates a `Bitmap` from the supplied data with an explicit codec
+    #[inline]
+    pub unsafe fn construct_with(data: &'a [u8], width: u16, height: u16, compression: Compression) -> Bitmap<'a> {
+        Bitmap { width: width, height: height, data: data, compression: compression }
     }
     /// Decompresses the bitmap data into the provided buffer
+    ///
+    /// The data is routed through the backend named by `compression`; every
+    /// backend must fill exactly `len()` bytes.
     #[inline]
     pub fn data(&self, out: &mut [u8]) {
         assert_eq!(out.len(), self.len() as usize);
-        let len = decompress(self.data, out);
-        assert_eq!(len, Ok(self.len() as usize));
+        match self.compression {
+            Compression::None => {
+                assert_eq!(self.data.len(), self.len() as usize);
+                out.copy_from_slice(self.data);
+            },
+            Compression::Lz4 => {
+                let len = decompress(self.data, out);
+                assert_eq!(len, Ok(self.len() as usize));
+            },
+            #[cfg(feature = "compress-zstd")]
+            Compression::Zstd => {
+                // ruzstd's own no_std `Read` trait keeps this backend free of
+                // `std`, matching the no_std goal of the slice-backed file.
+                use ::ruzstd::io::Read;
+                let mut dec = ::ruzstd::StreamingDecoder::new(self.data)
+                    .expect("invalid zstd frame");
+                let mut read = 0;
+                while read < out.len() {
+                    let n = dec.read(&mut out[read..]).expect("zstd decode error");
+                    assert!(n != 0, "zstd stream ended early");
+                    read += n;
+                }
+                // The frame must decode to exactly `len()` bytes: a stream that
+                // yields more would otherwise slip past the check below.
+                let mut extra = [0u8; 1];
+                assert_eq!(dec.read(&mut extra).expect("zstd decode error"), 0,
+                    "zstd stream produced more than the expected pixel count");
+                assert_eq!(read, self.len() as usize);
+            },
+        }
     }
     /// The raw (LZ4-compressed) bitmap data
     #[inline]
     pub fn raw_data(&self) -> &[u8] {
         self.data
     }
+    /// Serializes the decompressed bitmap to an in-memory PNG.
+    ///
+    /// This needs no external image crate: the pixels are decompressed,
+    /// the blue and red channels swapped to turn BGRA into RGBA, and the
+    /// result written out as a minimal but valid PNG stream.
+    #[cfg(feature = "std")]
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Writing into a `Vec` is infallible, so the result can be unwrapped.
+        self.write_png(&mut out).unwrap();
+        out
+    }
+    /// Writes the decompressed bitmap to `w` as a PNG stream.
+    ///
+    /// See `to_png` for the conversion performed. The emitted zlib stream
+    /// uses stored (uncompressed) DEFLATE blocks, which keeps the encoder
+    /// dependency-free at the cost of a larger file.
+    #[cfg(feature = "std")]
+    pub fn write_png<W: Write>(&self, w: &mut W) -> Result<(), IoError> {
+        let mut pixels = vec![0u8; self.len() as usize];
+        self.data(&mut pixels);
+        // BGRA -> RGBA.
+        for px in pixels.chunks_mut(4) {
+            px.swap(0, 2);
+        }
+        // Prefix every scanline with filter byte 0 (None). A zero-dimension
+        // bitmap has no scanlines, so skip the grouping (`chunks(0)` panics).
+        let stride = self.width as usize * 4;
+        let mut filtered = Vec::with_capacity(pixels.len() + self.height as usize);
+        if stride != 0 {
+            for line in pixels.chunks(stride) {
+                filtered.push(0);
+                filtered.extend_from_slice(line);
+            }
+        }
+        // 8-byte PNG signature.
+        try!(w.write_all(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]));
+        // IHDR: width, height, bit depth 8, color type 6 (RGBA), no interlace.
+        let mut ihdr = [0u8; 13];
+        ihdr[0..4].copy_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr[4..8].copy_from_slice(&(self.height as u32).to_be_bytes());
+        ihdr[8] = 8;
+        ihdr[9] = 6;
+        try!(write_chunk(w, b"IHDR", &ihdr));
+        // IDAT: a single zlib stream wrapping the filtered image.
+        try!(write_chunk(w, b"IDAT", &zlib_store(&filtered)));
+        // IEND.
+        try!(write_chunk(w, b"IEND", &[]));
+        Ok(())
+    }
+}
+
+/// Writes one PNG chunk: `length(u32 BE) + type + data + CRC32(type+data)`.
+#[cfg(feature = "std")]
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<(), IoError> {
+    try!(w.write_all(&(data.len() as u32).to_be_bytes()));
+    try!(w.write_all(kind));
+    try!(w.write_all(data));
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    try!(w.write_all(&crc.finish().to_be_bytes()));
+    Ok(())
+}
+
+/// Wraps `data` in a zlib stream built from stored DEFLATE blocks.
+#[cfg(feature = "std")]
+fn zlib_store(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.push(0x78);
+    out.push(0x01);
+    let mut chunks = data.chunks(0xFFFF).peekable();
+    if chunks.peek().is_none() {
+        // Empty input still needs a final, zero-length stored block.
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = chunks.next() {
+        let last = chunks.peek().is_none();
+        out.push(if last { 0x01 } else { 0x00 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Adler-32 checksum, as required by the zlib stream trailer.
+#[cfg(feature = "std")]
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// CRC-32 (as used by PNG chunks), computed with the standard polynomial.
+#[cfg(feature = "std")]
+struct Crc {
+    value: u32,
+}
+#[cfg(feature = "std")]
+impl Crc {
+    fn new() -> Crc {
+        Crc { value: 0xFFFFFFFF }
+    }
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            let mut c = (self.value ^ byte as u32) & 0xFF;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            self.value = c ^ (self.value >> 8);
+        }
+    }
+    fn finish(self) -> u32 {
+        self.value ^ 0xFFFFFFFF
+    }
 }
@@ -1,17 +1,18 @@
 // Copyright © 2015-2018, Peter Atashian
 //! Stuff for working with NX files
 
+#[cfg(feature = "std")]
 use memmap::Mmap;
-use std::error::Error as StdError;
-use std::fmt::{Display, Formatter};
-use std::fmt::Error as FmtError;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::slice::from_raw_parts;
+use core::str::{from_utf8, from_utf8_unchecked};
+#[cfg(feature = "std")]
 use std::fs::File as FsFile;
+#[cfg(feature = "std")]
 use std::io::Error as IoError;
-use std::mem::size_of;
+#[cfg(feature = "std")]
 use std::path::Path;
-use std::result::Result;
-use std::slice::from_raw_parts;
-use std::str::from_utf8_unchecked;
 
 use repr::{self, Header};
 
@@ -23,65 +24,160 @@ pub use node::{Type};
 #[derive(Debug)]
 pub enum Error {
     /// An internal IoError.
+    #[cfg(feature = "std")]
     Io(IoError),
     /// Magic value in header was incorrect.
     InvalidMagic,
     /// File was too short.
     TooShort,
+    /// A table entry pointed past the end of the file.
+    OffsetOutOfBounds,
+    /// A table's `count * entry_size` extended past the end of the file.
+    TableTruncated,
+    /// A table offset, or a string or bitmap offset stored in a table, was not
+    /// aligned for the type read there.
+    MisalignedTable,
+    /// A string table entry contained invalid UTF-8.
+    InvalidUtf8,
 }
-impl StdError for Error {
+#[cfg(feature = "std")]
+impl ::std::error::Error for Error {
     fn description(&self) -> &str {
         match self {
             &Error::Io(ref e) => e.description(),
             &Error::InvalidMagic => "Header magic value was invalid",
             &Error::TooShort => "File was too short for header",
+            &Error::OffsetOutOfBounds => "An offset pointed past the end of the file",
+            &Error::TableTruncated => "A table extended past the end of the file",
+            &Error::MisalignedTable => "A table or table entry offset was misaligned for its type",
+            &Error::InvalidUtf8 => "A string table entry was not valid UTF-8",
         }
     }
-    fn cause(&self) -> Option<&StdError> {
+    fn cause(&self) -> Option<&::std::error::Error> {
         match self {
             &Error::Io(ref e) => Some(e),
             _ => None,
         }
     }
 }
-impl Display for Error {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), FmtError> {
-        match self.cause() {
-            Some(cause) => write!(fmt, "{} ({})", self.description(), cause),
-            None => write!(fmt, "{}", self.description()),
+impl ::core::fmt::Display for Error {
+    fn fmt(&self, fmt: &mut ::core::fmt::Formatter) -> Result<(), ::core::fmt::Error> {
+        match self {
+            #[cfg(feature = "std")]
+            &Error::Io(ref e) => write!(fmt, "{}", e),
+            &Error::InvalidMagic => write!(fmt, "Header magic value was invalid"),
+            &Error::TooShort => write!(fmt, "File was too short for header"),
+            &Error::OffsetOutOfBounds => write!(fmt, "An offset pointed past the end of the file"),
+            &Error::TableTruncated => write!(fmt, "A table extended past the end of the file"),
+            &Error::MisalignedTable => write!(fmt, "A table or table entry offset was misaligned for its type"),
+            &Error::InvalidUtf8 => write!(fmt, "A string table entry was not valid UTF-8"),
         }
     }
 }
+#[cfg(feature = "std")]
 impl From<IoError> for Error {
     fn from(err: IoError) -> Error {
         Error::Io(err)
     }
 }
 
-/// A memory-mapped NX file.
-pub struct File {
+/// Owns the bytes backing a `File`, keeping them mapped or borrowed for its lifetime.
+enum Backing<'a> {
+    /// A memory-mapped file.
+    #[cfg(feature = "std")]
+    Mmap(Mmap),
+    /// An externally-owned slice of NX bytes.
+    Slice(PhantomData<&'a [u8]>),
+}
+
+/// An NX file, backed by either a memory-mapping or a borrowed slice.
+pub struct File<'a> {
     #[allow(dead_code)]
-    map: Mmap,
+    backing: Backing<'a>,
     data: *const u8,
+    len: usize,
     header: *const Header,
     nodetable: *const repr::Node,
     stringtable: *const u64,
     audiotable: *const u64,
     bitmaptable: *const u64,
+    /// Set once the tables have been proven in-bounds, enabling the safe API.
+    verified: bool,
 }
 
-impl File {
+impl<'a> File<'a> {
     /// Opens an NX file via memory-mapping. This also checks the magic bytes in the header.
     ///
     /// This is unsafe because it assumes the NX file is correct and UB may occur if
     /// there are mistakes.
-    pub unsafe fn open(path: &Path) -> Result<File, Error> {
+    #[cfg(feature = "std")]
+    pub unsafe fn open(path: &Path) -> Result<File<'static>, Error> {
         let file = try!(FsFile::open(path));
         let map = try!(Mmap::map(&file));
         if map.len() < size_of::<Header>() {
             return Err(Error::TooShort)
         }
+        let len = map.len();
         let data = map.as_ptr();
+        File::setup(Backing::Mmap(map), data, len)
+    }
+    /// Opens an NX file and fully validates its tables before returning.
+    ///
+    /// Unlike `open`, this checks that every table and every string, audio, and
+    /// bitmap offset lies within the mapped file and is properly aligned. On
+    /// success the file is memoized as verified, so the safe `*_checked`
+    /// accessors can be used without any `unsafe` at the call site. A hostile
+    /// or truncated file yields an `Error` rather than undefined behaviour.
+    #[cfg(feature = "std")]
+    pub fn open_checked(path: &Path) -> Result<File<'static>, Error> {
+        let file = try!(FsFile::open(path));
+        let map = try!(unsafe { Mmap::map(&file) });
+        if map.len() < size_of::<Header>() {
+            return Err(Error::TooShort)
+        }
+        let len = map.len();
+        let data = map.as_ptr();
+        let mut file = unsafe { try!(File::setup(Backing::Mmap(map), data, len)) };
+        try!(file.validate());
+        file.verified = true;
+        Ok(file)
+    }
+    /// Constructs an NX file from bytes already resident in memory.
+    ///
+    /// This performs the same magic and length checks as `open` but needs
+    /// neither `std` nor a memory-mapping, so it works under `no_std`. It is
+    /// unsafe for the same reason `open` is: the table offsets are trusted.
+    pub unsafe fn from_slice(bytes: &'a [u8]) -> Result<File<'a>, Error> {
+        if bytes.len() < size_of::<Header>() {
+            return Err(Error::TooShort)
+        }
+        File::setup(Backing::Slice(PhantomData), bytes.as_ptr(), bytes.len())
+    }
+    /// Constructs a fully-validated NX file from in-memory bytes.
+    ///
+    /// This is the `no_std`-friendly counterpart to `open_checked`: it runs the
+    /// same table validation over `bytes` and, on success, enables the safe
+    /// `*_checked` accessors.
+    pub fn from_slice_checked(bytes: &'a [u8]) -> Result<File<'a>, Error> {
+        if bytes.len() < size_of::<Header>() {
+            return Err(Error::TooShort)
+        }
+        let mut file = unsafe {
+            try!(File::setup(Backing::Slice(PhantomData), bytes.as_ptr(), bytes.len()))
+        };
+        try!(file.validate());
+        file.verified = true;
+        Ok(file)
+    }
+    /// Validates the header magic and wires up the table pointers from `data`.
+    #[inline]
+    unsafe fn setup(backing: Backing<'a>, data: *const u8, len: usize) -> Result<File<'a>, Error> {
+        // The header is dereferenced below, so its base must be aligned before
+        // the first read: a `&[u8]` from `from_slice_checked` has alignment 1
+        // and a misaligned `u32` magic read would be UB on untrusted input.
+        if (data as usize) % align_of::<Header>() != 0 {
+            return Err(Error::MisalignedTable)
+        }
         let header = data as *const Header;
         if (*header).magic != 0x34474B50 {
             return Err(Error::InvalidMagic)
@@ -91,15 +187,97 @@ impl File {
         let audiotable = data.offset((*header).audiooffset as isize) as *const u64;
         let bitmaptable = data.offset((*header).bitmapoffset as isize) as *const u64;
         Ok(File {
-            map: map,
+            backing: backing,
             data: data,
+            len: len,
             header: header,
             nodetable: nodetable,
             stringtable: stringtable,
             audiotable: audiotable,
             bitmaptable: bitmaptable,
+            verified: false,
         })
     }
+    /// Checks that every table region and table entry lies within the file.
+    fn validate(&self) -> Result<(), Error> {
+        let header = self.header();
+        // The base pointer itself must satisfy the tables' alignment: a slice
+        // handed to `from_slice_checked` can start at any address, so the
+        // `u64`/`Node` loads would otherwise be unaligned (UB).
+        if (self.data as usize) % align_of::<repr::Node>() != 0
+            || (self.data as usize) % align_of::<u64>() != 0 {
+            return Err(Error::MisalignedTable)
+        }
+        // Each table must fit, be aligned, and sit inside the mapped length.
+        try!(self.check_table(header.nodeoffset, header.nodecount,
+            size_of::<repr::Node>(), align_of::<repr::Node>()));
+        try!(self.check_table(header.stringoffset, header.stringcount,
+            size_of::<u64>(), align_of::<u64>()));
+        try!(self.check_table(header.audiooffset, header.audiocount,
+            size_of::<u64>(), align_of::<u64>()));
+        try!(self.check_table(header.bitmapoffset, header.bitmapcount,
+            size_of::<u64>(), align_of::<u64>()));
+        // Every string offset must point at an in-bounds length-prefixed blob.
+        for i in 0..header.stringcount {
+            let off = unsafe { *self.stringtable.offset(i as isize) } as usize;
+            if off.checked_add(size_of::<u16>()).map_or(true, |e| e > self.len) {
+                return Err(Error::OffsetOutOfBounds)
+            }
+            if off % align_of::<u16>() != 0 {
+                return Err(Error::MisalignedTable)
+            }
+            let size = unsafe { *(self.data.offset(off as isize) as *const u16) } as usize;
+            if off + size_of::<u16>() + size > self.len {
+                return Err(Error::OffsetOutOfBounds)
+            }
+            // The safe accessor returns `&str` via `from_utf8_unchecked`, so the
+            // contents must be proven valid UTF-8 here or that would be UB.
+            let bytes = unsafe {
+                from_raw_parts(self.data.offset((off + size_of::<u16>()) as isize), size)
+            };
+            if from_utf8(bytes).is_err() {
+                return Err(Error::InvalidUtf8)
+            }
+        }
+        // Every audio offset must at least point inside the file.
+        for i in 0..header.audiocount {
+            let off = unsafe { *self.audiotable.offset(i as isize) } as usize;
+            if off > self.len {
+                return Err(Error::OffsetOutOfBounds)
+            }
+        }
+        // Every bitmap offset must point at an in-bounds length-prefixed blob.
+        for i in 0..header.bitmapcount {
+            let off = unsafe { *self.bitmaptable.offset(i as isize) } as usize;
+            if off.checked_add(size_of::<u32>()).map_or(true, |e| e > self.len) {
+                return Err(Error::OffsetOutOfBounds)
+            }
+            if off % align_of::<u32>() != 0 {
+                return Err(Error::MisalignedTable)
+            }
+            let size = unsafe { *(self.data.offset(off as isize) as *const u32) } as usize;
+            if off + size_of::<u32>() + size > self.len {
+                return Err(Error::OffsetOutOfBounds)
+            }
+        }
+        Ok(())
+    }
+    /// Validates that a single table lies in-bounds and is correctly aligned.
+    #[inline]
+    fn check_table(&self, offset: u64, count: u32, entry_size: usize, align: usize) -> Result<(), Error> {
+        let offset = offset as usize;
+        if offset % align != 0 {
+            return Err(Error::MisalignedTable)
+        }
+        let bytes = match (count as usize).checked_mul(entry_size) {
+            Some(bytes) => bytes,
+            None => return Err(Error::TableTruncated),
+        };
+        match offset.checked_add(bytes) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(Error::TableTruncated),
+        }
+    }
     /// Gets the file header.
     #[inline]
     fn header(&self) -> &Header {
@@ -127,7 +305,7 @@ impl File {
     }
     /// Gets the root node of the file.
     #[inline]
-    pub fn root<'a>(&'a self) -> Node<'a> {
+    pub fn root<'b>(&'b self) -> Node<'b> {
         unsafe { Node::construct(&*self.nodetable, self) }
     }
     /// Gets the string at the specified index in the string table.
@@ -158,6 +336,64 @@ impl File {
         let len = *(ptr as *const u32);
         from_raw_parts(ptr.offset(4), len as usize)
     }
+    /// Whether this file has been validated by `open_checked`/`from_slice_checked`.
+    #[inline]
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+    /// Gets the root node, provided the file has been verified.
+    ///
+    /// Returns `None` on an unverified or empty file, so callers on untrusted
+    /// input never need an `unsafe` block.
+    #[inline]
+    pub fn root_checked<'b>(&'b self) -> Option<Node<'b>> {
+        if self.verified && self.header().nodecount > 0 {
+            Some(unsafe { Node::construct(&*self.nodetable, self) })
+        } else {
+            None
+        }
+    }
+    /// Gets the string at `index`, or `None` if out of range on a verified file.
+    #[inline]
+    pub fn get_str_checked(&self, index: u32) -> Option<&str> {
+        if self.verified && index < self.header().stringcount {
+            Some(unsafe { self.get_str(index) })
+        } else {
+            None
+        }
+    }
+    /// Gets the node data at `index`, or `None` if out of range on a verified file.
+    #[inline]
+    pub fn get_node_checked(&self, index: u32) -> Option<&repr::Node> {
+        if self.verified && index < self.header().nodecount {
+            Some(unsafe { self.get_node(index) })
+        } else {
+            None
+        }
+    }
+    /// Gets the audio data at `index`, or `None` if out of range on a verified file.
+    #[inline]
+    pub fn get_audio_checked(&self, index: u32, length: u32) -> Option<&[u8]> {
+        if !self.verified || index >= self.header().audiocount {
+            return None
+        }
+        // `length` comes from the file, so the payload must still be proven to
+        // fit before constructing the slice — `validate()` only bounded `off`.
+        let off = unsafe { *self.audiotable.offset(index as isize) } as usize;
+        match off.checked_add(length as usize) {
+            Some(end) if end <= self.len => Some(unsafe { self.get_audio(index, length) }),
+            _ => None,
+        }
+    }
+    /// Gets the bitmap data at `index`, or `None` if out of range on a verified file.
+    #[inline]
+    pub fn get_bitmap_checked(&self, index: u32) -> Option<&[u8]> {
+        if self.verified && index < self.header().bitmapcount {
+            Some(unsafe { self.get_bitmap(index) })
+        } else {
+            None
+        }
+    }
 }
-unsafe impl Send for File {}
-unsafe impl Sync for File {}
+unsafe impl<'a> Send for File<'a> {}
+unsafe impl<'a> Sync for File<'a> {}